@@ -1,9 +1,11 @@
 use anyhow::{
     anyhow,
     bail,
+    Context,
     Error,
     Result
 };
+use std::fmt;
 #[cfg(feature="serde")]
 use serde::{
     Deserialize,
@@ -12,22 +14,21 @@ use serde::{
 use std::{
     fs::File,
     io::{
+	BufRead,
 	BufReader,
-	Read
+	Read,
+	Write
     },
     path::Path
 };
 
 #[derive(Copy,Clone,Debug)]
 struct RawHeader {
-    #[allow(dead_code)]
     star0:i32,
-    #[allow(dead_code)]
     star1:i32,
     starn:i32,
     stnum:i32,
     mprop:bool,
-    #[allow(dead_code)]
     nmag:i32,
     nbent:i32
 }
@@ -102,60 +103,216 @@ pub struct Ybsc {
     /// If the proper motion values are valid
     pub have_proper_motion:bool,
 
+    /// Sequence number of the first star in the file (`star0` header field)
+    pub star0:i32,
+
+    /// Sequence number of the first catalog entry (`star1` header field)
+    pub star1:i32,
+
+    /// Number of magnitudes present for each entry (`nmag` header field)
+    pub nmag:i32,
+
+    /// Byte order the file was decoded in, re-used when writing so that a
+    /// catalog round-trips in its original order
+    pub endian:Endian,
+
     /// The entries of the catalog
     pub stars:Vec<Star>
 }
 
-fn read_char<R:Read>(mut r:R)->Result<char> {
-    let mut x = [0;1];
-    r.read_exact(&mut x)?;
-    char::from_u32(x[0] as u32)
-	.ok_or_else(|| anyhow!("Invalid char {}",x[0]))
+/// Byte order in which catalog primitives are stored
+#[cfg_attr(feature="serde",derive(Deserialize,Serialize))]
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum Endian {
+    /// Least-significant byte first
+    Little,
+
+    /// Most-significant byte first (FORTRAN/IDL origin)
+    Big
 }
 
-fn read_i16<R:Read>(mut r:R)->Result<i16> {
-    let mut x = [0;2];
-    r.read_exact(&mut x)?;
-    Ok(i16::from_le_bytes(x))
+/// Location of a decode failure within a catalog stream
+///
+/// Attached as [`anyhow`] context to every read error so that a corrupt
+/// file reports where it went wrong, e.g. `entry 4213 at offset 0x20ebc`.
+#[derive(Copy,Clone,Debug)]
+pub struct DecodeError {
+    /// Byte offset within the reader's stream at which the failing entry
+    /// (or the header) starts
+    pub offset:u64,
+
+    /// Zero-based index of the entry being decoded, or `None` while the
+    /// header itself is being read
+    pub entry_index:Option<usize>
 }
 
-fn read_i32<R:Read>(mut r:R)->Result<i32> {
-    let mut x = [0;4];
-    r.read_exact(&mut x)?;
-    Ok(i32::from_le_bytes(x))
+impl fmt::Display for DecodeError {
+    fn fmt(&self,f:&mut fmt::Formatter)->fmt::Result {
+	match self.entry_index {
+	    Some(i) => write!(f,"entry {} at offset {:#x}",i,self.offset),
+	    None => write!(f,"header at offset {:#x}",self.offset)
+	}
+    }
 }
 
-fn read_u32<R:Read>(mut r:R)->Result<u32> {
-    let mut x = [0;4];
-    r.read_exact(&mut x)?;
-    Ok(u32::from_le_bytes(x))
+impl std::error::Error for DecodeError {}
+
+/// Wraps a reader together with the byte order its primitives decode in
+/// and a running byte offset used to locate decode failures
+struct Reader<R> {
+    inner:R,
+    endian:Endian,
+    pos:u64
 }
 
-fn read_u64<R:Read>(mut r:R)->Result<u64> {
-    let mut x = [0;8];
-    r.read_exact(&mut x)?;
-    Ok(u64::from_le_bytes(x))
+impl<R:Read> Reader<R> {
+    fn new(inner:R,endian:Endian)->Self {
+	Self {
+	    inner,
+	    endian,
+	    pos:0
+	}
+    }
+
+    /// Current byte offset into the wrapped stream
+    fn pos(&self)->u64 {
+	self.pos
+    }
+
+    fn read_char(&mut self)->Result<char> {
+	let mut x = [0;1];
+	self.inner.read_exact(&mut x)?;
+	self.pos += 1;
+	char::from_u32(x[0] as u32)
+	    .ok_or_else(|| anyhow!("Invalid char {}",x[0]))
+    }
+
+    fn read_i16(&mut self)->Result<i16> {
+	let mut x = [0;2];
+	self.inner.read_exact(&mut x)?;
+	self.pos += 2;
+	Ok(match self.endian {
+	    Endian::Little => i16::from_le_bytes(x),
+	    Endian::Big => i16::from_be_bytes(x)
+	})
+    }
+
+    fn read_i32(&mut self)->Result<i32> {
+	let mut x = [0;4];
+	self.inner.read_exact(&mut x)?;
+	self.pos += 4;
+	Ok(match self.endian {
+	    Endian::Little => i32::from_le_bytes(x),
+	    Endian::Big => i32::from_be_bytes(x)
+	})
+    }
+
+    fn read_u32(&mut self)->Result<u32> {
+	let mut x = [0;4];
+	self.inner.read_exact(&mut x)?;
+	self.pos += 4;
+	Ok(match self.endian {
+	    Endian::Little => u32::from_le_bytes(x),
+	    Endian::Big => u32::from_be_bytes(x)
+	})
+    }
+
+    fn read_u64(&mut self)->Result<u64> {
+	let mut x = [0;8];
+	self.inner.read_exact(&mut x)?;
+	self.pos += 8;
+	Ok(match self.endian {
+	    Endian::Little => u64::from_le_bytes(x),
+	    Endian::Big => u64::from_be_bytes(x)
+	})
+    }
+
+    fn read_f32(&mut self)->Result<f32> {
+	let x = self.read_u32()?;
+	Ok(f32::from_bits(x))
+    }
+
+    fn read_f64(&mut self)->Result<f64> {
+	let x = self.read_u64()?;
+	Ok(f64::from_bits(x))
+    }
 }
 
-fn read_f32<R:Read>(r:R)->Result<f32> {
-    let x = read_u32(r)?;
-    Ok(f32::from_bits(x))
+/// Wraps a writer together with the byte order its primitives encode in,
+/// mirroring [`Reader`] so that a catalog round-trips in the same order it
+/// was decoded
+struct Writer<W> {
+    inner:W,
+    endian:Endian
 }
 
-fn read_f64<R:Read>(r:R)->Result<f64> {
-    let x = read_u64(r)?;
-    Ok(f64::from_bits(x))
+impl<W:Write> Writer<W> {
+    fn new(inner:W,endian:Endian)->Self {
+	Self {
+	    inner,
+	    endian
+	}
+    }
+
+    fn write_char(&mut self,c:char)->Result<()> {
+	let u = c as u32;
+	if u > 0xff {
+	    bail!("Char {:?} is not representable in a single byte",c);
+	}
+	self.inner.write_all(&[u as u8])?;
+	Ok(())
+    }
+
+    fn write_i16(&mut self,x:i16)->Result<()> {
+	self.inner.write_all(&match self.endian {
+	    Endian::Little => x.to_le_bytes(),
+	    Endian::Big => x.to_be_bytes()
+	})?;
+	Ok(())
+    }
+
+    fn write_i32(&mut self,x:i32)->Result<()> {
+	self.inner.write_all(&match self.endian {
+	    Endian::Little => x.to_le_bytes(),
+	    Endian::Big => x.to_be_bytes()
+	})?;
+	Ok(())
+    }
+
+    fn write_u32(&mut self,x:u32)->Result<()> {
+	self.inner.write_all(&match self.endian {
+	    Endian::Little => x.to_le_bytes(),
+	    Endian::Big => x.to_be_bytes()
+	})?;
+	Ok(())
+    }
+
+    fn write_u64(&mut self,x:u64)->Result<()> {
+	self.inner.write_all(&match self.endian {
+	    Endian::Little => x.to_le_bytes(),
+	    Endian::Big => x.to_be_bytes()
+	})?;
+	Ok(())
+    }
+
+    fn write_f32(&mut self,x:f32)->Result<()> {
+	self.write_u32(x.to_bits())
+    }
+
+    fn write_f64(&mut self,x:f64)->Result<()> {
+	self.write_u64(x.to_bits())
+    }
 }
 
 impl RawHeader {
-    pub fn read_from<R:Read>(mut r:R)->Result<Self> {
-	let star0 = read_i32(&mut r)?;
-	let star1 = read_i32(&mut r)?;
-	let starn = read_i32(&mut r)?;
-	let stnum = read_i32(&mut r)?;
-	let mprop = read_i32(&mut r)? != 0;
-	let nmag = read_i32(&mut r)?;
-	let nbent = read_i32(&mut r)?;
+    fn read_from<R:Read>(rd:&mut Reader<R>)->Result<Self> {
+	let star0 = rd.read_i32()?;
+	let star1 = rd.read_i32()?;
+	let starn = rd.read_i32()?;
+	let stnum = rd.read_i32()?;
+	let mprop = rd.read_i32()? != 0;
+	let nmag = rd.read_i32()?;
+	let nbent = rd.read_i32()?;
 	Ok(Self {
 	    star0,
 	    star1,
@@ -166,6 +323,39 @@ impl RawHeader {
 	    nbent
 	})
     }
+
+    fn write_to<W:Write>(&self,w:&mut Writer<W>)->Result<()> {
+	w.write_i32(self.star0)?;
+	w.write_i32(self.star1)?;
+	w.write_i32(self.starn)?;
+	w.write_i32(self.stnum)?;
+	w.write_i32(if self.mprop { 1 } else { 0 })?;
+	w.write_i32(self.nmag)?;
+	w.write_i32(self.nbent)?;
+	Ok(())
+    }
+
+    /// Whether a decoded header is internally consistent, used to pick the
+    /// byte order during auto-detection
+    fn is_sane(&self)->bool {
+	self.nbent == 32
+	    && (0..=2).contains(&self.stnum)
+	    && self.starn != 0
+    }
+}
+
+fn parse_header(bytes:&[u8;28],endian:Endian)->Result<RawHeader> {
+    let mut rd = Reader::new(&bytes[..],endian);
+    RawHeader::read_from(&mut rd)
+}
+
+fn detect_endian(bytes:&[u8;28])->Result<Endian> {
+    for endian in [Endian::Little,Endian::Big] {
+	if parse_header(bytes,endian)?.is_sane() {
+	    return Ok(endian);
+	}
+    }
+    bail!("Header is not a valid YBSC catalog under either byte order");
 }
 
 impl<T,U> Entry<T,U> {
@@ -175,16 +365,16 @@ impl<T,U> Entry<T,U> {
 }
 
 impl RawEntry {
-    pub fn read_from<R:Read>(mut r:R)->Result<Self> {
-	let xno = read_f32(&mut r)?;
-	let sra0 = read_f64(&mut r)?;
-	let sdec0 = read_f64(&mut r)?;
-	let is0 = read_char(&mut r)?;
-	let is1 = read_char(&mut r)?;
+    fn read_from<R:Read>(rd:&mut Reader<R>)->Result<Self> {
+	let xno = rd.read_f32()?;
+	let sra0 = rd.read_f64()?;
+	let sdec0 = rd.read_f64()?;
+	let is0 = rd.read_char()?;
+	let is1 = rd.read_char()?;
 	let is = [is0,is1];
-	let mag = read_i16(&mut r)?;
-	let xrpm = read_f32(&mut r)?;
-	let xdpm = read_f32(&mut r)?;
+	let mag = rd.read_i16()?;
+	let xrpm = rd.read_f32()?;
+	let xdpm = rd.read_f32()?;
 	Ok(Self {
 	    xno,
 	    sra0,
@@ -195,6 +385,18 @@ impl RawEntry {
 	    xdpm
 	})
     }
+
+    fn write_to<W:Write>(&self,w:&mut Writer<W>)->Result<()> {
+	w.write_f32(self.xno)?;
+	w.write_f64(self.sra0)?;
+	w.write_f64(self.sdec0)?;
+	w.write_char(self.is[0])?;
+	w.write_char(self.is[1])?;
+	w.write_i16(self.mag)?;
+	w.write_f32(self.xrpm)?;
+	w.write_f32(self.xdpm)?;
+	Ok(())
+    }
 }
 
 impl TryFrom<RawEntry> for Star {
@@ -227,6 +429,29 @@ impl TryFrom<RawEntry> for Star {
     }
 }
 
+impl From<Star> for RawEntry {
+    fn from(star:Star)->RawEntry {
+	let Entry {
+	    xno,
+	    sra0,
+	    sdec0,
+	    is,
+	    mag,
+	    xrpm,
+	    xdpm
+	} = star;
+	Entry {
+	    xno:xno as f32,
+	    sra0,
+	    sdec0,
+	    is,
+	    mag:(mag * 100.0).round() as i16,
+	    xrpm,
+	    xdpm
+	}
+    }
+}
+
 impl TryFrom<i32> for IdType {
     type Error = Error;
     
@@ -240,10 +465,67 @@ impl TryFrom<i32> for IdType {
     }
 }
 
-impl Ybsc {
-    /// Decode a catalog file from a reader
-    pub fn read_from<R:Read>(mut r:R)->Result<Self> {
-	let hdr = RawHeader::read_from(&mut r)?;
+/// Lazy iterator over the entries of a catalog
+///
+/// The 28-byte header is parsed when the iterator is constructed, exposing
+/// the catalog-wide metadata ([`equinox`](StarIter::equinox),
+/// [`id_type`](StarIter::id_type),
+/// [`have_proper_motion`](StarIter::have_proper_motion) and the declared
+/// [`star_count`](StarIter::star_count)); each call to
+/// [`next`](Iterator::next) then decodes a single [`RawEntry`], skips it if
+/// it is not [`valid`](Entry::valid), and converts the survivor into a
+/// [`Star`] on the fly. This avoids materializing the whole catalog when a
+/// caller only wants to scan or filter it once.
+pub struct StarIter<R> {
+    rd:Reader<R>,
+
+    /// Which equinox-epoch the data refers to
+    pub equinox:Equinox,
+
+    /// Which kind of star ID, if any, this catalog contains
+    pub id_type:IdType,
+
+    /// If the proper motion values are valid
+    pub have_proper_motion:bool,
+
+    /// Number of entries declared in the header
+    pub star_count:usize,
+
+    star0:i32,
+    star1:i32,
+    nmag:i32,
+    remaining:usize,
+    index:usize
+}
+
+impl<R:Read> StarIter<R> {
+    /// Start iterating over a catalog, auto-detecting the byte order
+    ///
+    /// See [`Ybsc::read_from`] for how the byte order is selected.
+    pub fn read_from(mut r:R)->Result<Self> {
+	let ctx = || DecodeError { offset:0,entry_index:None };
+	let mut hdr_bytes = [0u8;28];
+	r.read_exact(&mut hdr_bytes).with_context(ctx)?;
+	let endian = detect_endian(&hdr_bytes).with_context(ctx)?;
+	let hdr = parse_header(&hdr_bytes,endian).with_context(ctx)?;
+	let mut rd = Reader::new(r,endian);
+	// The 28-byte header was consumed above; keep the offset in sync
+	// with the underlying stream so entry offsets are absolute.
+	rd.pos = 28;
+	Self::with_header(rd,hdr)
+    }
+
+    /// Start iterating over a catalog using an explicit byte order
+    pub fn read_from_with_endian(r:R,endian:Endian)->Result<Self> {
+	let mut rd = Reader::new(r,endian);
+	let offset = rd.pos();
+	let hdr = RawHeader::read_from(&mut rd)
+	    .with_context(|| DecodeError { offset,entry_index:None })?;
+	Self::with_header(rd,hdr)
+	    .with_context(|| DecodeError { offset,entry_index:None })
+    }
+
+    fn with_header(rd:Reader<R>,hdr:RawHeader)->Result<Self> {
 	if hdr.nbent != 32 {
 	    bail!("Number of bytes per entry {} is not 32",
 		  hdr.nbent);
@@ -254,28 +536,159 @@ impl Ybsc {
 	    } else {
 		(Equinox::B1950,hdr.starn as usize)
 	    };
-	let mut stars = Vec::with_capacity(nstar);
-	let have_proper_motion = hdr.mprop;
 	let id_type : IdType = hdr.stnum.try_into()?;
-	for _ in 0..nstar {
-	    let entry = RawEntry::read_from(&mut r)?;
-	    if entry.valid() {
-		let star = Star::try_from(entry)?;
-		stars.push(star);
-	    }
-	}
 	Ok(Self {
+	    rd,
 	    equinox,
-	    have_proper_motion,
 	    id_type,
+	    have_proper_motion:hdr.mprop,
+	    star_count:nstar,
+	    star0:hdr.star0,
+	    star1:hdr.star1,
+	    nmag:hdr.nmag,
+	    remaining:nstar,
+	    index:0
+	})
+    }
+
+    /// Collect the remaining entries into an owned [`Ybsc`]
+    fn collect_ybsc(mut self)->Result<Ybsc> {
+	let mut stars = Vec::with_capacity(self.remaining);
+	for star in self.by_ref() {
+	    stars.push(star?);
+	}
+	Ok(Ybsc {
+	    equinox:self.equinox,
+	    have_proper_motion:self.have_proper_motion,
+	    star0:self.star0,
+	    star1:self.star1,
+	    nmag:self.nmag,
+	    endian:self.rd.endian,
+	    id_type:self.id_type,
 	    stars
 	})
     }
+}
+
+impl<R:Read> Iterator for StarIter<R> {
+    type Item = Result<Star>;
+
+    fn next(&mut self)->Option<Self::Item> {
+	while self.remaining > 0 {
+	    self.remaining -= 1;
+	    let index = self.index;
+	    self.index += 1;
+	    let offset = self.rd.pos();
+	    let ctx = || DecodeError { offset,entry_index:Some(index) };
+	    let entry = match RawEntry::read_from(&mut self.rd).with_context(ctx) {
+		Ok(entry) => entry,
+		Err(e) => {
+		    self.remaining = 0;
+		    return Some(Err(e));
+		}
+	    };
+	    if entry.valid() {
+		let star = Star::try_from(entry).with_context(ctx);
+		if star.is_err() {
+		    self.remaining = 0;
+		}
+		return Some(star);
+	    }
+	}
+	None
+    }
+}
+
+impl Ybsc {
+    /// Decode a catalog file from a reader, auto-detecting the byte order
+    ///
+    /// The 28-byte header is read and interpreted under both byte orders;
+    /// the one under which it is self-consistent (`nbent == 32` and the
+    /// `starn`/`stnum` fields in sane ranges) is selected. Use
+    /// [`Ybsc::read_from_with_endian`] when the byte order is known in
+    /// advance.
+    ///
+    /// Blank/placeholder entries (those for which [`Entry::valid`] is
+    /// false) are discarded rather than retained, so a decode followed by
+    /// a [`write_to`](Ybsc::write_to) is *not* byte-identical to an input
+    /// that contained blank slots: both the dropped records and the header
+    /// `starn` count reflect only the retained stars.
+    pub fn read_from<R:Read>(r:R)->Result<Self> {
+	StarIter::read_from(r)?.collect_ybsc()
+    }
+
+    /// Decode a catalog file from a reader using an explicit byte order
+    pub fn read_from_with_endian<R:Read>(r:R,endian:Endian)->Result<Self> {
+	StarIter::read_from_with_endian(r,endian)?.collect_ybsc()
+    }
+
+    /// Encode a catalog file to a writer, in the catalog's own
+    /// [`endian`](Ybsc::endian) byte order
+    pub fn write_to<W:Write>(&self,w:W)->Result<()> {
+	let mut w = Writer::new(w,self.endian);
+	let nstar = self.stars.len() as i32;
+	let starn = match self.equinox {
+	    Equinox::B1950 => nstar,
+	    Equinox::J2000 => -nstar
+	};
+	let stnum = match self.id_type {
+	    IdType::None => 0,
+	    IdType::SeeCatalog => 1,
+	    IdType::Included => 2
+	};
+	let hdr = RawHeader {
+	    star0:self.star0,
+	    star1:self.star1,
+	    starn,
+	    stnum,
+	    mprop:self.have_proper_motion,
+	    nmag:self.nmag,
+	    nbent:32
+	};
+	hdr.write_to(&mut w)?;
+	for star in &self.stars {
+	    let raw = RawEntry::from(*star);
+	    raw.write_to(&mut w)?;
+	}
+	Ok(())
+    }
+
+    /// Encode a catalog file to a writer using an explicit byte order
+    pub fn write_to_with_endian<W:Write>(&self,w:W,endian:Endian)->Result<()> {
+	Ybsc { endian,..self.clone() }.write_to(w)
+    }
 
     /// Convenience function for loading a file
+    ///
+    /// The leading bytes are sniffed for a known container magic; when one
+    /// is found the reader is transparently wrapped in the matching
+    /// streaming decoder (`1F 8B` &rarr; gzip, behind the `gzip` feature;
+    /// `28 B5 2F FD` &rarr; zstd, behind the `zstd` feature) before
+    /// parsing. Uncompressed files are handed to [`Ybsc::read_from`]
+    /// directly.
     pub fn load<P:AsRef<Path>>(path:P)->Result<Self> {
 	let fd = File::open(path)?;
-	let br = BufReader::new(fd);
+	let mut br = BufReader::new(fd);
+	let magic = {
+	    let buf = br.fill_buf()?;
+	    let n = buf.len().min(4);
+	    let mut m = [0u8;4];
+	    m[..n].copy_from_slice(&buf[..n]);
+	    (m,n)
+	};
+	let (magic,n) = magic;
+	if n >= 2 && magic[0] == 0x1f && magic[1] == 0x8b {
+	    #[cfg(feature="gzip")]
+	    return Ybsc::read_from(flate2::read::GzDecoder::new(br));
+	    #[cfg(not(feature="gzip"))]
+	    bail!("File is gzip-compressed but the `gzip` feature is not enabled");
+	}
+	if n >= 4 && magic == [0x28,0xb5,0x2f,0xfd] {
+	    #[cfg(feature="zstd")]
+	    return Ybsc::read_from(zstd::stream::read::Decoder::new(br)?);
+	    #[cfg(not(feature="zstd"))]
+	    bail!("File is zstd-compressed but the `zstd` feature is not enabled");
+	}
 	Ybsc::read_from(br)
     }
 }